@@ -1,6 +1,8 @@
 use failure::Error;
 use quicli::prelude::*;
+use std::collections::HashMap;
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use yaml_rust::YamlLoader;
@@ -15,6 +17,21 @@ pub struct BuildSpec {
     #[serde(default = "default_context")]
     context: PathBuf,
     target: Option<String>,
+    /// Values passed to `docker build` as `--build-arg KEY=VALUE` flags
+    #[serde(default)]
+    build_args: HashMap<String, String>,
+    /// Shell commands run sequentially before the build, e.g. to prepare
+    /// files or fetch toolchain-specific values referenced by the Dockerfile
+    #[serde(default)]
+    pre_build: Vec<String>,
+    /// A Dockerfile template to render with `vars` before building, instead
+    /// of building `dockerfile` directly
+    template: Option<PathBuf>,
+    /// Values substituted into `{{ var }}` placeholders in `template`.
+    /// Requires `serde_yaml` as a normal `[dependencies]` entry, not just a
+    /// dev-dependency, since this type is now part of the parsed config.
+    #[serde(default)]
+    vars: HashMap<String, serde_yaml::Value>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -23,6 +40,16 @@ pub struct YamlSpec {
     key: String,
 }
 
+/// A Dockerfile supplied inline in `floki.yaml`, built by piping its
+/// contents to `docker build -f -` rather than reading a file on disk
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct DockerfileSpec {
+    dockerfile_inline: String,
+    #[serde(default = "default_context")]
+    context: PathBuf,
+    target: Option<String>,
+}
+
 fn default_dockerfile() -> PathBuf {
     "Dockerfile".into()
 }
@@ -31,11 +58,175 @@ fn default_context() -> PathBuf {
     ".".into()
 }
 
+/// Hash the contents of an inline Dockerfile so that rebuilds of an
+/// unchanged Dockerfile reuse the existing `:floki` tagged image
+fn hash_content(content: &str) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Render a template variable for substitution into a Dockerfile template.
+/// Sequences (e.g. a list of packages) are space-joined so they can be
+/// dropped straight into a shell command like `apt-get install`.
+fn render_template_value(value: &serde_yaml::Value) -> String {
+    match value {
+        serde_yaml::Value::String(s) => s.clone(),
+        serde_yaml::Value::Bool(b) => b.to_string(),
+        serde_yaml::Value::Number(n) => n.to_string(),
+        serde_yaml::Value::Sequence(items) => items
+            .iter()
+            .map(render_template_value)
+            .collect::<Vec<_>>()
+            .join(" "),
+        serde_yaml::Value::Null => String::new(),
+        other => format!("{:?}", other),
+    }
+}
+
+/// A minimal `{{ var }}` template engine, sufficient for substituting
+/// scalars and package lists into a Dockerfile template
+fn render_template(
+    template: &str,
+    vars: &HashMap<String, serde_yaml::Value>,
+) -> Result<String, Error> {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after_open = &rest[start + 2..];
+        let end = after_open
+            .find("}}")
+            .ok_or_else(|| FlokiError::InvalidDockerfileTemplate {
+                reason: "unterminated {{ ... }} placeholder".into(),
+            })?;
+
+        let key = after_open[..end].trim();
+        let value = vars
+            .get(key)
+            .ok_or_else(|| FlokiError::MissingTemplateVariable {
+                variable: key.to_string(),
+            })?;
+        output.push_str(&render_template_value(value));
+
+        rest = &after_open[end + 2..];
+    }
+    output.push_str(rest);
+
+    Ok(output)
+}
+
+/// Owns a rendered Dockerfile written to disk by `render_template`, and
+/// removes it on drop so it's cleaned up on every return path out of
+/// `obtain_image` (a completed build, a failed `spawn`, or any other `?`
+/// propagated before that point) rather than only after a successful wait.
+struct RenderedDockerfileGuard {
+    path: Option<PathBuf>,
+}
+
+impl RenderedDockerfileGuard {
+    fn none() -> Self {
+        RenderedDockerfileGuard { path: None }
+    }
+
+    fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+}
+
+impl Drop for RenderedDockerfileGuard {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// The container engine binary used to build/pull/inspect images. Podman is
+/// argument-compatible with docker for the commands we run, so selecting it
+/// only changes the program name we invoke.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerEngine {
+    Docker,
+    Podman,
+}
+
+impl ContainerEngine {
+    fn program(self) -> &'static str {
+        match self {
+            ContainerEngine::Docker => "docker",
+            ContainerEngine::Podman => "podman",
+        }
+    }
+}
+
+impl Default for ContainerEngine {
+    fn default() -> Self {
+        ContainerEngine::Docker
+    }
+}
+
+/// Resolve the container engine to use. The `FLOKI_CONTAINER_ENGINE`
+/// environment variable (`docker` or `podman`) takes precedence over the
+/// default of `docker`; an unrecognized value is a hard error rather than
+/// a silent fallback.
+///
+/// The top-level floki config (not present in this extracted module) should
+/// also carry a `docker`/`podman` enum field and prefer it over the
+/// environment variable, falling back to this function when unset.
+pub fn resolve_engine() -> Result<ContainerEngine, Error> {
+    match std::env::var("FLOKI_CONTAINER_ENGINE") {
+        Ok(value) => parse_engine(&value),
+        Err(_) => Ok(ContainerEngine::Docker),
+    }
+}
+
+/// Pure value -> `ContainerEngine` mapping, split out from `resolve_engine`
+/// so the parsing logic can be tested without touching process environment
+/// (env vars are global state, and mutating them from parallel test threads
+/// is a race).
+fn parse_engine(value: &str) -> Result<ContainerEngine, Error> {
+    if value.eq_ignore_ascii_case("docker") {
+        Ok(ContainerEngine::Docker)
+    } else if value.eq_ignore_ascii_case("podman") {
+        Ok(ContainerEngine::Podman)
+    } else {
+        Err(FlokiError::UnknownContainerEngine {
+            value: value.to_string(),
+        }
+        .into())
+    }
+}
+
+/// Controls when `Image::ensure_available` pulls an image
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PullPolicy {
+    /// Always pull, even if the image already exists locally
+    Always,
+    /// Only pull if the image is not already present locally
+    IfNotPresent,
+    /// Never pull; error out if the image is not already present locally
+    Never,
+}
+
+impl Default for PullPolicy {
+    fn default() -> Self {
+        PullPolicy::IfNotPresent
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Image {
     Name(String),
     Build { build: BuildSpec },
+    Dockerfile { dockerfile: DockerfileSpec },
     Yaml { yaml: YamlSpec },
 }
 
@@ -45,6 +236,10 @@ impl Image {
         match *self {
             Image::Name(ref s) => Ok(s.clone()),
             Image::Build { ref build } => Ok(build.name.clone() + ":floki"),
+            Image::Dockerfile { ref dockerfile } => Ok(format!(
+                "floki-inline-{:x}:floki",
+                hash_content(&dockerfile.dockerfile_inline)
+            )),
             Image::Yaml { ref yaml } => {
                 let contents = fs::read_to_string(&yaml.file)?;
                 let raw = YamlLoader::load_from_str(&contents)?;
@@ -74,33 +269,132 @@ impl Image {
 
     /// Do the required work to get the image, and then return
     /// it's name
-    pub fn obtain_image(&self, floki_root: &Path) -> Result<String, Error> {
+    pub fn obtain_image(
+        &self,
+        floki_root: &Path,
+        engine: ContainerEngine,
+    ) -> Result<String, Error> {
         match *self {
             // Deal with the case where want to build an image
             Image::Build { ref build } => {
-                let mut command = Command::new("docker");
+                for cmd in &build.pre_build {
+                    let exit_status = Command::new("sh")
+                        .arg("-c")
+                        .arg(cmd)
+                        .current_dir(floki_root)
+                        .spawn()?
+                        .wait()?;
+                    if !exit_status.success() {
+                        return Err(FlokiError::FailedToRunPreBuildCommand {
+                            command: cmd.clone(),
+                            exit_status: FlokiSubprocessExitStatus {
+                                process_description: format!("pre_build command: {}", cmd),
+                                exit_status,
+                            },
+                        }
+                        .into());
+                    }
+                }
+
+                // A rendered template is written next to the user's project
+                // (rather than into the shared, world-readable system temp
+                // directory) and created atomically so a pre-existing file
+                // or symlink at the target path is never reused. The guard
+                // removes it on every exit from this arm, not just a
+                // completed build.
+                let rendered_file = if let Some(template) = &build.template {
+                    let template_contents = fs::read_to_string(floki_root.join(template))?;
+                    let rendered = render_template(&template_contents, &build.vars)?;
+                    let rendered_path = floki_root.join(format!(
+                        ".floki-dockerfile-{:x}-{}",
+                        hash_content(&rendered),
+                        std::process::id()
+                    ));
+                    let mut file = fs::OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(&rendered_path)?;
+                    let guard = RenderedDockerfileGuard {
+                        path: Some(rendered_path),
+                    };
+                    file.write_all(rendered.as_bytes())?;
+                    guard
+                } else {
+                    RenderedDockerfileGuard::none()
+                };
+                let dockerfile_path = rendered_file
+                    .path()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_else(|| floki_root.join(&build.dockerfile));
+
+                let mut command = Command::new(engine.program());
                 command
                     .arg("build")
                     .arg("-t")
                     .arg(self.name()?)
                     .arg("-f")
-                    .arg(&floki_root.join(&build.dockerfile));
+                    .arg(&dockerfile_path);
 
                 if let Some(target) = &build.target {
                     command.arg("--target").arg(target);
                 }
 
+                for (key, value) in &build.build_args {
+                    command.arg("--build-arg").arg(format!("{}={}", key, value));
+                }
+
                 let exit_status = command
                     .arg(&floki_root.join(&build.context))
                     .spawn()?
                     .wait()?;
+
                 if exit_status.success() {
                     Ok(self.name()?)
                 } else {
                     Err(FlokiError::FailedToBuildImage {
                         image: self.name()?,
                         exit_status: FlokiSubprocessExitStatus {
-                            process_description: "docker build".into(),
+                            process_description: format!("{} build", engine.program()),
+                            exit_status,
+                        },
+                    }
+                    .into())
+                }
+            }
+            // Deal with the case where we build from an inline Dockerfile
+            // piped over stdin, rather than a file on disk
+            Image::Dockerfile { ref dockerfile } => {
+                let mut command = Command::new(engine.program());
+                command
+                    .arg("build")
+                    .arg("-t")
+                    .arg(self.name()?)
+                    .arg("-f")
+                    .arg("-");
+
+                if let Some(target) = &dockerfile.target {
+                    command.arg("--target").arg(target);
+                }
+
+                let mut child = command
+                    .arg(&floki_root.join(&dockerfile.context))
+                    .stdin(Stdio::piped())
+                    .spawn()?;
+
+                child
+                    .stdin
+                    .take()
+                    .expect("stdin was piped")
+                    .write_all(dockerfile.dockerfile_inline.as_bytes())?;
+
+                let exit_status = child.wait()?;
+                if exit_status.success() {
+                    Ok(self.name()?)
+                } else {
+                    Err(FlokiError::FailedToBuildImage {
+                        image: self.name()?,
+                        exit_status: FlokiSubprocessExitStatus {
+                            process_description: format!("{} build", engine.program()),
                             exit_status,
                         },
                     }
@@ -111,14 +405,116 @@ impl Image {
             _ => Ok(self.name()?),
         }
     }
+
+    /// Ensure the image is present locally, pulling it if `policy` requires
+    pub fn ensure_available(
+        &self,
+        policy: PullPolicy,
+        engine: ContainerEngine,
+        auth: Option<&AuthConfig>,
+    ) -> Result<(), Error> {
+        let name = self.name()?;
+        match policy {
+            PullPolicy::IfNotPresent => {
+                if !image_exists_locally(&name, engine)? {
+                    pull_image(&name, engine, auth)?;
+                }
+                Ok(())
+            }
+            PullPolicy::Always => pull_image(&name, engine, auth),
+            PullPolicy::Never => {
+                if image_exists_locally(&name, engine)? {
+                    Ok(())
+                } else {
+                    Err(FlokiError::ImageNotPresentLocally { image: name }.into())
+                }
+            }
+        }
+    }
 }
 
 // Now we have some functions which are useful in general
 
-/// Wrapper to pull an image by it's name
-pub fn pull_image(name: &str) -> Result<(), Error> {
+/// Credentials for a private registry, used to `docker login` before a pull.
+/// The password may be given directly or resolved from an environment
+/// variable, so it need not be written into `floki.yaml` in plain text.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuthConfig {
+    pub registry: String,
+    pub username: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    password_env: Option<String>,
+}
+
+impl AuthConfig {
+    fn resolve_password(&self) -> Result<String, Error> {
+        if let Some(ref password) = self.password {
+            return Ok(password.clone());
+        }
+        if let Some(ref var) = self.password_env {
+            return std::env::var(var).map_err(|_| {
+                FlokiError::FailedToResolveAuthEnvironmentVariable {
+                    variable: var.clone(),
+                }
+                .into()
+            });
+        }
+        Err(FlokiError::MissingRegistryPassword {
+            registry: self.registry.clone(),
+        }
+        .into())
+    }
+}
+
+/// Log in to a private registry so a subsequent pull can succeed
+fn login(engine: ContainerEngine, auth: &AuthConfig) -> Result<(), Error> {
+    let password = auth.resolve_password()?;
+
+    let mut child = Command::new(engine.program())
+        .arg("login")
+        .arg(&auth.registry)
+        .arg("-u")
+        .arg(&auth.username)
+        .arg("--password-stdin")
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    child
+        .stdin
+        .take()
+        .expect("stdin was piped")
+        .write_all(password.as_bytes())?;
+
+    let exit_status = child.wait()?;
+    if exit_status.success() {
+        Ok(())
+    } else {
+        Err(FlokiError::FailedToLogin {
+            registry: auth.registry.clone(),
+            exit_status: FlokiSubprocessExitStatus {
+                process_description: format!("{} login", engine.program()),
+                exit_status,
+            },
+        }
+        .into())
+    }
+}
+
+/// Wrapper to pull an image by it's name, logging in to the registry first
+/// if credentials are supplied
+pub fn pull_image(
+    name: &str,
+    engine: ContainerEngine,
+    auth: Option<&AuthConfig>,
+) -> Result<(), Error> {
+    if let Some(auth) = auth {
+        login(engine, auth)?;
+    }
+
     debug!("Pulling image: {}", name);
-    let exit_status = Command::new("docker")
+    let exit_status = Command::new(engine.program())
         .arg("pull")
         .arg(name)
         .spawn()?
@@ -130,7 +526,7 @@ pub fn pull_image(name: &str) -> Result<(), Error> {
         Err(FlokiError::FailedToPullImage {
             image: name.into(),
             exit_status: FlokiSubprocessExitStatus {
-                process_description: "docker pull".into(),
+                process_description: format!("{} pull", engine.program()),
                 exit_status,
             },
         }
@@ -139,8 +535,8 @@ pub fn pull_image(name: &str) -> Result<(), Error> {
 }
 
 /// Determine whether an image exists locally
-pub fn image_exists_locally(name: &str) -> Result<bool, Error> {
-    let ret = Command::new("docker")
+pub fn image_exists_locally(name: &str, engine: ContainerEngine) -> Result<bool, Error> {
+    let ret = Command::new(engine.program())
         .args(&["history", name])
         .stdin(Stdio::null())
         .stdout(Stdio::null())
@@ -184,6 +580,51 @@ mod test {
                     dockerfile: "Dockerfile.test".into(),
                     context: "./context".into(),
                     target: Some("builder".into()),
+                    build_args: HashMap::new(),
+                    pre_build: Vec::new(),
+                    template: None,
+                    vars: HashMap::new(),
+                },
+            },
+        };
+        let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual == expected);
+    }
+
+    #[test]
+    fn test_image_spec_by_build_spec_with_args_and_pre_build() {
+        let yaml = "image:\n  build:\n    name: foo\n    build_args:\n      TOOLCHAIN: stable\n    pre_build:\n      - echo preparing";
+        let expected = TestImage {
+            image: Image::Build {
+                build: BuildSpec {
+                    name: "foo".into(),
+                    dockerfile: default_dockerfile(),
+                    context: default_context(),
+                    target: None,
+                    build_args: {
+                        let mut map = HashMap::new();
+                        map.insert("TOOLCHAIN".into(), "stable".into());
+                        map
+                    },
+                    pre_build: vec!["echo preparing".into()],
+                    template: None,
+                    vars: HashMap::new(),
+                },
+            },
+        };
+        let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual == expected);
+    }
+
+    #[test]
+    fn test_image_spec_by_dockerfile_spec() {
+        let yaml = "image:\n  dockerfile:\n    dockerfile_inline: |\n      FROM alpine\n      RUN echo hi\n    context: ./context\n    target: builder";
+        let expected = TestImage {
+            image: Image::Dockerfile {
+                dockerfile: DockerfileSpec {
+                    dockerfile_inline: "FROM alpine\nRUN echo hi\n".into(),
+                    context: "./context".into(),
+                    target: Some("builder".into()),
                 },
             },
         };
@@ -191,6 +632,17 @@ mod test {
         assert!(actual == expected);
     }
 
+    #[test]
+    fn test_dockerfile_spec_name_is_stable_for_same_content() {
+        let dockerfile = DockerfileSpec {
+            dockerfile_inline: "FROM alpine\n".into(),
+            context: default_context(),
+            target: None,
+        };
+        let image = Image::Dockerfile { dockerfile };
+        assert!(image.name().unwrap() == image.name().unwrap());
+    }
+
     /// Determine if a given program is installed in the current environment.
     fn program_is_installed(program: &str) -> bool {
         which(program).is_ok()
@@ -211,12 +663,111 @@ mod test {
         // was previously!), as pulling here means that the second subtest
         // below would then fail.
         let existent_image = "docker:stable-dind";
-        pull_image(existent_image).unwrap();
-        assert!(image_exists_locally(existent_image).unwrap());
+        pull_image(existent_image, ContainerEngine::Docker, None).unwrap();
+        assert!(image_exists_locally(existent_image, ContainerEngine::Docker).unwrap());
 
         // Now test an image that doesn't exist, and therefore shouldn't
         // exist locally.
         let non_existent_image = "doesnt_exist:re4lly--sh0u1dnt-exist";
-        assert!(!image_exists_locally(non_existent_image).unwrap());
+        assert!(!image_exists_locally(non_existent_image, ContainerEngine::Docker).unwrap());
+    }
+
+    #[test]
+    fn test_render_template_substitutes_scalars_and_lists() {
+        let mut vars = HashMap::new();
+        vars.insert(
+            "base".to_string(),
+            serde_yaml::Value::String("alpine:3.18".into()),
+        );
+        vars.insert(
+            "ship_packages".to_string(),
+            serde_yaml::Value::Sequence(vec![
+                serde_yaml::Value::String("curl".into()),
+                serde_yaml::Value::String("git".into()),
+            ]),
+        );
+
+        let template = "FROM {{ base }}\nRUN apk add --no-cache {{ ship_packages }}\n";
+        let rendered = render_template(template, &vars).unwrap();
+
+        assert!(rendered == "FROM alpine:3.18\nRUN apk add --no-cache curl git\n");
+    }
+
+    #[test]
+    fn test_render_template_errors_on_missing_variable() {
+        let vars = HashMap::new();
+        let template = "FROM {{ base }}\n";
+        assert!(render_template(template, &vars).is_err());
+    }
+
+    #[test]
+    fn test_rendered_dockerfile_guard_removes_file_on_drop() {
+        let path = std::env::temp_dir().join(format!(
+            "floki-test-rendered-dockerfile-guard-{}",
+            std::process::id()
+        ));
+        fs::write(&path, "FROM alpine\n").unwrap();
+        assert!(path.exists());
+
+        {
+            let _guard = RenderedDockerfileGuard {
+                path: Some(path.clone()),
+            };
+        }
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_ensure_available_never_policy_errors_if_absent() {
+        assert!(
+            program_is_installed("docker"),
+            "docker required for this test but not installed!"
+        );
+
+        let image = Image::Name("doesnt_exist:re4lly--sh0u1dnt-exist".into());
+        assert!(image
+            .ensure_available(PullPolicy::Never, ContainerEngine::Docker, None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_engine_docker() {
+        assert!(parse_engine("docker").unwrap() == ContainerEngine::Docker);
+        assert!(parse_engine("Docker").unwrap() == ContainerEngine::Docker);
+    }
+
+    #[test]
+    fn test_parse_engine_podman() {
+        assert!(parse_engine("podman").unwrap() == ContainerEngine::Podman);
+        assert!(parse_engine("Podman").unwrap() == ContainerEngine::Podman);
+    }
+
+    #[test]
+    fn test_parse_engine_errors_on_unknown_value() {
+        assert!(parse_engine("pdoman").is_err());
+    }
+
+    #[test]
+    fn test_auth_config_resolves_password_from_env() {
+        std::env::set_var("TEST_FLOKI_REGISTRY_PASSWORD", "hunter2");
+        let auth = AuthConfig {
+            registry: "registry.example.com".into(),
+            username: "alice".into(),
+            password: None,
+            password_env: Some("TEST_FLOKI_REGISTRY_PASSWORD".into()),
+        };
+        assert!(auth.resolve_password().unwrap() == "hunter2");
+    }
+
+    #[test]
+    fn test_auth_config_errors_without_password() {
+        let auth = AuthConfig {
+            registry: "registry.example.com".into(),
+            username: "alice".into(),
+            password: None,
+            password_env: None,
+        };
+        assert!(auth.resolve_password().is_err());
     }
 }